@@ -0,0 +1,120 @@
+use std::{net::SocketAddr, sync::atomic::Ordering, sync::Arc};
+
+use log::info;
+use reqwest::Url;
+use serde::Serialize;
+use tokio::sync::mpsc::UnboundedSender;
+use warp::Filter;
+
+use crate::torrent_state::{LivePeerSnapshot, TorrentState};
+
+/// An embedded HTTP server exposing read-only stats plus a couple of control
+/// routes to drive a running torrent from scripts. It shares the
+/// [`TorrentState`] through its `Arc` and forwards control requests to the
+/// manager over the same kind of channel the DHT peer-adder uses, so it
+/// doesn't need to know about the manager handle's internals.
+#[derive(Clone)]
+pub struct HttpApi {
+    state: Arc<TorrentState>,
+    tracker_tx: UnboundedSender<Url>,
+    peer_tx: UnboundedSender<SocketAddr>,
+}
+
+#[derive(Serialize)]
+struct StatsResponse {
+    downloaded_bytes: u64,
+    uploaded_bytes: u64,
+    fetched_bytes: u64,
+    needed_bytes: u64,
+    left_to_download_bytes: u64,
+    percent_complete: f64,
+    connecting_peers: usize,
+    live_peers: usize,
+}
+
+#[derive(Serialize)]
+struct PeersResponse {
+    peers: Vec<LivePeerSnapshot>,
+}
+
+#[derive(Serialize)]
+struct Accepted {
+    ok: bool,
+}
+
+impl HttpApi {
+    pub fn new(
+        state: Arc<TorrentState>,
+        tracker_tx: UnboundedSender<Url>,
+        peer_tx: UnboundedSender<SocketAddr>,
+    ) -> Self {
+        Self {
+            state,
+            tracker_tx,
+            peer_tx,
+        }
+    }
+
+    fn stats(&self) -> StatsResponse {
+        let downloaded = self.state.get_downloaded();
+        let needed = self.state.needed;
+        let agg = self.state.locked.read().peers.stats();
+        StatsResponse {
+            downloaded_bytes: downloaded,
+            uploaded_bytes: self.state.get_uploaded(),
+            fetched_bytes: self.state.stats.fetched_bytes.load(Ordering::Relaxed),
+            needed_bytes: needed,
+            left_to_download_bytes: self.state.get_left_to_download(),
+            percent_complete: if needed == 0 {
+                100.0
+            } else {
+                downloaded as f64 / needed as f64 * 100.0
+            },
+            connecting_peers: agg.connecting,
+            live_peers: agg.live,
+        }
+    }
+
+    fn peers(&self) -> PeersResponse {
+        PeersResponse {
+            peers: self.state.locked.read().peers.live_snapshot(),
+        }
+    }
+
+    /// Run the server until the process exits.
+    pub async fn serve(self, addr: SocketAddr) -> anyhow::Result<()> {
+        let this = self.clone();
+        let stats = warp::path!("stats")
+            .and(warp::get())
+            .map(move || warp::reply::json(&this.stats()));
+
+        let this = self.clone();
+        let peers = warp::path!("peers")
+            .and(warp::get())
+            .map(move || warp::reply::json(&this.peers()));
+
+        let this = self.clone();
+        let add_tracker = warp::path!("add_tracker")
+            .and(warp::post())
+            .and(warp::body::json())
+            .map(move |url: Url| {
+                let ok = this.tracker_tx.send(url).is_ok();
+                warp::reply::json(&Accepted { ok })
+            });
+
+        let this = self.clone();
+        let add_peer = warp::path!("add_peer")
+            .and(warp::post())
+            .and(warp::body::json())
+            .map(move |addr: SocketAddr| {
+                let ok = this.peer_tx.send(addr).is_ok();
+                warp::reply::json(&Accepted { ok })
+            });
+
+        let routes = stats.or(peers).or(add_tracker).or(add_peer);
+
+        info!("http api listening on {}", addr);
+        warp::serve(routes).run(addr).await;
+        Ok(())
+    }
+}
@@ -0,0 +1,84 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::type_aliases::BF;
+
+/// The on-disk resume state for a single torrent, keyed by info_hash. Mirrors
+/// the resume-database idea from the udpt config's `db_path`: a tiny sidecar
+/// file we can load on startup to avoid re-hashing everything from scratch.
+#[derive(Serialize, Deserialize)]
+pub struct ResumeData {
+    /// The "have" bitfield, one bit per piece, as the raw byte buffer.
+    pub have: Vec<u8>,
+    /// The `only_files` selection this bitfield was produced against, if any.
+    /// A resume file is only valid for a matching selection.
+    pub only_files: Option<Vec<usize>>,
+    /// The torrent state's `needed` byte count when the bitfield was saved. A
+    /// resume file whose value differs from the current run's is ignored, as a
+    /// cheap sanity check that the output hasn't changed underneath us.
+    pub total_length: u64,
+}
+
+impl ResumeData {
+    pub fn new(have: &BF, only_files: Option<Vec<usize>>, total_length: u64) -> Self {
+        Self {
+            have: have.as_raw_slice().to_vec(),
+            only_files,
+            total_length,
+        }
+    }
+
+    pub fn bitfield(&self) -> BF {
+        BF::from_vec(self.have.clone())
+    }
+}
+
+fn resume_path(resume_dir: &Path, info_hash: &[u8; 20]) -> PathBuf {
+    let mut name = String::with_capacity(40);
+    for b in info_hash {
+        name.push_str(&format!("{:02x}", b));
+    }
+    name.push_str(".resume");
+    resume_dir.join(name)
+}
+
+/// Load the resume state for `info_hash`, if a file exists and parses. A
+/// missing or unreadable file is not an error - we simply start from scratch.
+pub fn load(resume_dir: &Path, info_hash: &[u8; 20]) -> Option<ResumeData> {
+    let path = resume_path(resume_dir, info_hash);
+    let bytes = match std::fs::read(&path) {
+        Ok(b) => b,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+        Err(e) => {
+            warn!("error reading resume file {:?}: {}", path, e);
+            return None;
+        }
+    };
+    match serde_json::from_slice(&bytes) {
+        Ok(data) => {
+            debug!("loaded resume file {:?}", path);
+            Some(data)
+        }
+        Err(e) => {
+            warn!("error parsing resume file {:?}, ignoring: {}", path, e);
+            None
+        }
+    }
+}
+
+/// Flush the resume state for `info_hash` to disk, creating `resume_dir` if
+/// needed. Written to a temp file and renamed so a crash mid-write can't
+/// corrupt an existing resume file.
+pub fn save(resume_dir: &Path, info_hash: &[u8; 20], data: &ResumeData) -> anyhow::Result<()> {
+    std::fs::create_dir_all(resume_dir)
+        .with_context(|| format!("error creating resume dir {:?}", resume_dir))?;
+    let path = resume_path(resume_dir, info_hash);
+    let tmp = path.with_extension("resume.tmp");
+    let bytes = serde_json::to_vec(data).context("error serializing resume data")?;
+    std::fs::write(&tmp, &bytes).with_context(|| format!("error writing {:?}", tmp))?;
+    std::fs::rename(&tmp, &path).with_context(|| format!("error renaming to {:?}", path))?;
+    Ok(())
+}
@@ -2,14 +2,16 @@ use std::{
     collections::{HashMap, HashSet},
     fs::File,
     net::SocketAddr,
+    path::Path,
     sync::{
         atomic::{AtomicU64, Ordering},
         Arc,
     },
+    time::{Duration, Instant},
 };
 
 use futures::{stream::FuturesUnordered, StreamExt};
-use log::warn;
+use log::{info, warn};
 use parking_lot::{Mutex, RwLock};
 use tokio::sync::mpsc::Sender;
 
@@ -20,6 +22,7 @@ use crate::{
     lengths::{ChunkInfo, Lengths, ValidPieceIndex},
     peer_binary_protocol::{Handshake, Message, MessageOwned, Piece},
     peer_state::{LivePeerState, PeerState},
+    resume::{self, ResumeData},
     torrent_metainfo::TorrentMetaV1Owned,
     type_aliases::{PeerHandle, BF},
 };
@@ -39,12 +42,63 @@ impl From<&ChunkInfo> for InflightRequest {
     }
 }
 
+/// The number of peers we keep unchoked at any time (the optimistic unchoke is
+/// additional to this).
+pub const DEFAULT_UNCHOKE_SLOTS: usize = 4;
+
+/// Reconnection backoff: the first retry waits this long, doubling each time.
+const RECONNECT_BACKOFF_MIN: Duration = Duration::from_secs(4);
+/// ...up to this cap.
+const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(300);
+/// Give up on a peer after this many failed connection attempts.
+const RECONNECT_MAX_ATTEMPTS: u32 = 8;
+
+/// A peer that disconnected and is eligible for a later reconnection attempt.
+struct DeadPeer {
+    attempts: u32,
+    next_retry: Instant,
+}
+
+impl DeadPeer {
+    fn new(now: Instant) -> Self {
+        let mut p = Self {
+            attempts: 0,
+            next_retry: now,
+        };
+        p.bump(now);
+        p
+    }
+    /// Record another failed attempt and push out the next retry time with
+    /// exponential backoff.
+    fn bump(&mut self, now: Instant) {
+        let backoff = RECONNECT_BACKOFF_MIN
+            .saturating_mul(1u32 << self.attempts.min(16))
+            .min(RECONNECT_BACKOFF_MAX);
+        self.attempts += 1;
+        self.next_retry = now + backoff;
+    }
+}
+
 #[derive(Default)]
 pub struct PeerStates {
     states: HashMap<PeerHandle, PeerState>,
     seen_peers: HashSet<SocketAddr>,
     inflight_pieces: HashSet<ValidPieceIndex>,
     tx: HashMap<PeerHandle, Arc<tokio::sync::mpsc::Sender<MessageOwned>>>,
+    // Choking bookkeeping. Lives here rather than on LivePeerState because the
+    // choker needs to rank across all peers at once.
+    am_choking: HashMap<PeerHandle, bool>,
+    peer_interested: HashMap<PeerHandle, bool>,
+    // Bytes fed to us / sent to the peer since the last choker round, used as a
+    // rolling rate estimate. Reset every round.
+    downloaded_recent: HashMap<PeerHandle, u64>,
+    uploaded_recent: HashMap<PeerHandle, u64>,
+    optimistic_unchoke: Option<PeerHandle>,
+    // Peers that disconnected and are waiting on a backoff before we retry.
+    dead_peers: HashMap<SocketAddr, DeadPeer>,
+    // How many connected peers advertise each piece, indexed by piece. Drives
+    // rarest-first selection. Grown lazily to the piece count.
+    piece_availability: Vec<u16>,
 }
 
 #[derive(Debug, Default)]
@@ -53,6 +107,17 @@ pub struct AggregatePeerStats {
     pub live: usize,
 }
 
+/// A read-only view of one live peer, exposed over the HTTP API.
+#[derive(Debug, serde::Serialize)]
+pub struct LivePeerSnapshot {
+    pub addr: SocketAddr,
+    pub i_am_choked: bool,
+    pub am_choking: bool,
+    pub peer_interested: bool,
+    pub recent_downloaded_bytes: u64,
+    pub recent_uploaded_bytes: u64,
+}
+
 impl PeerStates {
     pub fn stats(&self) -> AggregatePeerStats {
         self.states
@@ -70,7 +135,17 @@ impl PeerStates {
         addr: SocketAddr,
         tx: tokio::sync::mpsc::Sender<MessageOwned>,
     ) -> Option<PeerHandle> {
-        if self.seen_peers.contains(&addr) {
+        // Already connecting or live - don't open a second connection.
+        if self.states.contains_key(&addr) {
+            return None;
+        }
+        // Seen before and not in a retry-eligible dead state - reject. A peer
+        // parked in dead_peers (because the reconnect task picked it up) is
+        // allowed through so transient failures get another chance. We keep the
+        // dead_peers entry (and its attempt count) until the peer actually
+        // reaches Live, so a failed reconnect keeps growing the backoff instead
+        // of resetting to the first attempt every time.
+        if self.seen_peers.contains(&addr) && !self.dead_peers.contains_key(&addr) {
             return None;
         }
         let handle = self.add(addr, tx)?;
@@ -109,8 +184,45 @@ impl PeerStates {
     pub fn drop_peer(&mut self, handle: PeerHandle) -> Option<PeerState> {
         let result = self.states.remove(&handle);
         self.tx.remove(&handle);
+        self.am_choking.remove(&handle);
+        self.peer_interested.remove(&handle);
+        self.downloaded_recent.remove(&handle);
+        self.uploaded_recent.remove(&handle);
+        if self.optimistic_unchoke == Some(handle) {
+            self.optimistic_unchoke = None;
+        }
         result
     }
+    /// Move a dropped peer's address into the retry-eligible dead set with a
+    /// fresh backoff. Called from [`TorrentState::drop_peer`].
+    pub fn mark_dead(&mut self, addr: SocketAddr, now: Instant) {
+        match self.dead_peers.get_mut(&addr) {
+            Some(dead) => dead.bump(now),
+            None => {
+                self.dead_peers.insert(addr, DeadPeer::new(now));
+            }
+        }
+    }
+    /// Addresses whose backoff has elapsed and that are worth reconnecting to.
+    /// Peers that have exhausted [`RECONNECT_MAX_ATTEMPTS`] are dropped for good.
+    pub fn reconnect_candidates(&mut self, now: Instant) -> Vec<SocketAddr> {
+        self.dead_peers
+            .retain(|_, dead| dead.attempts < RECONNECT_MAX_ATTEMPTS);
+        self.dead_peers
+            .iter()
+            .filter(|(_, dead)| dead.next_retry <= now)
+            .map(|(addr, _)| *addr)
+            .collect()
+    }
+    pub fn set_peer_interested(&mut self, handle: PeerHandle, interested: bool) {
+        self.peer_interested.insert(handle, interested);
+    }
+    pub fn record_downloaded_from(&mut self, handle: PeerHandle, bytes: u64) {
+        *self.downloaded_recent.entry(handle).or_default() += bytes;
+    }
+    pub fn record_uploaded_to(&mut self, handle: PeerHandle, bytes: u64) {
+        *self.uploaded_recent.entry(handle).or_default() += bytes;
+    }
     pub fn mark_i_am_choked(&mut self, handle: PeerHandle, is_choked: bool) -> Option<bool> {
         let live = self.get_live_mut(handle)?;
         let prev = live.i_am_choked;
@@ -125,15 +237,157 @@ impl PeerStates {
         let live = self.get_live_mut(handle)?;
         let bitfield = BF::from_vec(bitfield);
         let prev = live.bitfield.take();
+        // Snapshot the new/old set bits so we can rebase the availability
+        // counts (a peer may send a fresh bitfield replacing an old one).
+        let added: Vec<usize> = bitfield.iter_ones().collect();
+        let removed: Vec<usize> = prev
+            .as_ref()
+            .map(|p| p.iter_ones().collect())
+            .unwrap_or_default();
         live.bitfield = Some(bitfield);
+        for i in removed {
+            self.decrement_piece_availability(i);
+        }
+        for i in added {
+            self.increment_piece_availability(i);
+        }
         Some(prev)
     }
+    /// Apply an incoming `Message::Have`: record the bit in the peer's
+    /// bitfield (if one has been received) and raise the piece's availability.
+    pub fn peer_has_piece(&mut self, handle: PeerHandle, piece: usize) {
+        // Only count the piece when this Have actually flips a previously-unset
+        // bit. A redundant Have would otherwise double-count, and a Have that
+        // arrives before the bitfield is intentionally ignored here - it gets
+        // counted by update_bitfield_from_vec once the bitfield lands - so the
+        // increments stay balanced against the drop_peer decrement, which walks
+        // the final bitfield.
+        let newly_set = match self.get_live_mut(handle).and_then(|l| l.bitfield.as_mut()) {
+            Some(bf) => match bf.get_mut(piece) {
+                Some(mut bit) if !*bit => {
+                    *bit = true;
+                    true
+                }
+                _ => false,
+            },
+            None => false,
+        };
+        if newly_set {
+            self.increment_piece_availability(piece);
+        }
+    }
+    /// Record that a peer now has a piece (e.g. from a `Message::Have`).
+    pub fn increment_piece_availability(&mut self, piece: usize) {
+        if piece >= self.piece_availability.len() {
+            self.piece_availability.resize(piece + 1, 0);
+        }
+        self.piece_availability[piece] = self.piece_availability[piece].saturating_add(1);
+    }
+    fn decrement_piece_availability(&mut self, piece: usize) {
+        if let Some(c) = self.piece_availability.get_mut(piece) {
+            *c = c.saturating_sub(1);
+        }
+    }
     pub fn clone_tx(&self, handle: PeerHandle) -> Option<Arc<Sender<MessageOwned>>> {
         Some(self.tx.get(&handle)?.clone())
     }
     pub fn remove_inflight_piece(&mut self, piece: ValidPieceIndex) -> bool {
         self.inflight_pieces.remove(&piece)
     }
+
+    /// A snapshot of every live peer's address, choke/interested flags and
+    /// recent download rate, for the HTTP `/peers` endpoint.
+    pub fn live_snapshot(&self) -> Vec<LivePeerSnapshot> {
+        self.states
+            .iter()
+            .filter_map(|(handle, state)| {
+                let live = match state {
+                    PeerState::Live(l) => l,
+                    _ => return None,
+                };
+                Some(LivePeerSnapshot {
+                    addr: *handle,
+                    i_am_choked: live.i_am_choked,
+                    am_choking: *self.am_choking.get(handle).unwrap_or(&true),
+                    peer_interested: *self.peer_interested.get(handle).unwrap_or(&false),
+                    recent_downloaded_bytes: self.downloaded_recent.get(handle).copied().unwrap_or(0),
+                    recent_uploaded_bytes: self.uploaded_recent.get(handle).copied().unwrap_or(0),
+                })
+            })
+            .collect()
+    }
+
+    /// Decide which live peers to choke/unchoke this round and return the tx
+    /// handles for the peers whose state changed, paired with the new choke
+    /// value (`true` = choke). Resets the rolling byte counters.
+    fn compute_choke_decisions(
+        &mut self,
+        seeding: bool,
+        rotate_optimistic: bool,
+    ) -> Vec<(Arc<Sender<MessageOwned>>, bool)> {
+        use rand::seq::IteratorRandom;
+
+        // Rank interested live peers by recent rate, best first.
+        let mut interested: Vec<(PeerHandle, u64)> = self
+            .states
+            .iter()
+            .filter_map(|(h, s)| match s {
+                PeerState::Live(_) if *self.peer_interested.get(h).unwrap_or(&false) => {
+                    let rate = if seeding {
+                        self.uploaded_recent.get(h).copied().unwrap_or(0)
+                    } else {
+                        self.downloaded_recent.get(h).copied().unwrap_or(0)
+                    };
+                    Some((*h, rate))
+                }
+                _ => None,
+            })
+            .collect();
+        interested.sort_unstable_by(|a, b| b.1.cmp(&a.1));
+
+        let mut unchoked: HashSet<PeerHandle> = interested
+            .iter()
+            .take(DEFAULT_UNCHOKE_SLOTS)
+            .map(|(h, _)| *h)
+            .collect();
+
+        // Optimistic unchoke: a random interested peer outside the top slots.
+        if rotate_optimistic {
+            let mut rng = rand::thread_rng();
+            self.optimistic_unchoke = interested
+                .iter()
+                .map(|(h, _)| *h)
+                .filter(|h| !unchoked.contains(h))
+                .choose(&mut rng);
+        }
+        if let Some(h) = self.optimistic_unchoke {
+            if self.states.contains_key(&h) {
+                unchoked.insert(h);
+            }
+        }
+
+        // Apply to every live peer, emitting a message only on a real change.
+        let mut changes = Vec::new();
+        let live: Vec<PeerHandle> = self
+            .states
+            .iter()
+            .filter(|(_, s)| matches!(s, PeerState::Live(_)))
+            .map(|(h, _)| *h)
+            .collect();
+        for h in live {
+            let should_choke = !unchoked.contains(&h);
+            let was_choking = self.am_choking.insert(h, should_choke).unwrap_or(true);
+            if was_choking != should_choke {
+                if let Some(tx) = self.tx.get(&h) {
+                    changes.push((tx.clone(), should_choke));
+                }
+            }
+        }
+
+        self.downloaded_recent.clear();
+        self.uploaded_recent.clear();
+        changes
+    }
 }
 
 pub struct TorrentStateLocked {
@@ -181,13 +435,59 @@ impl TorrentState {
         who_sent: PeerHandle,
         chunk_info: ChunkInfo,
     ) -> anyhow::Result<Vec<u8>> {
-        read_chunk(
+        let data = read_chunk(
             &self.torrent,
             &self.files,
             &self.lengths,
             who_sent,
             chunk_info,
-        )
+        )?;
+        // Feed the choker's rolling upload-rate estimate for this peer.
+        self.locked
+            .write()
+            .peers
+            .record_uploaded_to(who_sent, data.len() as u64);
+        Ok(data)
+    }
+
+    /// Handle an incoming `Interested`/`NotInterested` message: the choker
+    /// only considers peers that are interested in us.
+    pub fn on_peer_interested(&self, handle: PeerHandle, interested: bool) {
+        self.locked
+            .write()
+            .peers
+            .set_peer_interested(handle, interested);
+    }
+
+    /// Handle an incoming `Message::Have` by raising the advertised piece's
+    /// availability so rarest-first selection stays accurate for peers that
+    /// announce pieces incrementally rather than via a single bitfield.
+    pub fn on_peer_have(&self, handle: PeerHandle, piece: u32) {
+        self.locked
+            .write()
+            .peers
+            .peer_has_piece(handle, piece as usize);
+    }
+
+    /// Central dispatch for an incoming peer message. The peer connection loop
+    /// calls this for every message it reads, so all the shared-state updates
+    /// that the choker and rarest-first selection depend on happen in one
+    /// place. Data-carrying messages (Bitfield/Request/Piece) keep their
+    /// dedicated handling on the hot path; this covers the lightweight
+    /// state-transition messages.
+    pub fn on_incoming_message(&self, handle: PeerHandle, msg: &MessageOwned) {
+        match msg {
+            Message::Choke => {
+                self.locked.write().peers.mark_i_am_choked(handle, true);
+            }
+            Message::Unchoke => {
+                self.locked.write().peers.mark_i_am_choked(handle, false);
+            }
+            Message::Interested => self.on_peer_interested(handle, true),
+            Message::NotInterested => self.on_peer_interested(handle, false),
+            Message::Have(piece) => self.on_peer_have(handle, *piece),
+            _ => {}
+        }
     }
 
     pub fn write_chunk_blocking(
@@ -203,7 +503,13 @@ impl TorrentState {
             who_sent,
             data,
             chunk_info,
-        )
+        )?;
+        // Feed the choker's rolling download-rate estimate for this peer.
+        self.locked
+            .write()
+            .peers
+            .record_downloaded_from(who_sent, chunk_info.size as u64);
+        Ok(())
     }
 
     pub fn get_next_needed_piece(&self, peer_handle: PeerHandle) -> Option<ValidPieceIndex> {
@@ -233,16 +539,40 @@ impl TorrentState {
         }
         let mut g = self.locked.write();
         let n = {
-            let mut n_opt = None;
+            // The needed pieces this peer actually advertises.
             let bf = g.peers.get_live(peer_handle)?.bitfield.as_ref()?;
-            for n in g.chunks.get_needed_pieces().iter_ones() {
-                if bf.get(n).map(|v| *v) == Some(true) {
-                    n_opt = Some(n);
-                    break;
-                }
+            let candidates: Vec<usize> = g
+                .chunks
+                .get_needed_pieces()
+                .iter_ones()
+                .filter(|n| bf.get(*n).map(|v| *v) == Some(true))
+                .collect();
+            if candidates.is_empty() {
+                return None;
             }
 
-            self.lengths.validate_piece_index(n_opt? as u32)?
+            let mut rng = rand::thread_rng();
+            let picked = if self.get_downloaded() == 0 {
+                // Initial phase: no complete piece yet, so pick randomly rather
+                // than converging on the single rarest block nobody can finish.
+                use rand::seq::SliceRandom;
+                *candidates.choose(&mut rng)?
+            } else {
+                // Rarest first: lowest availability wins, ties broken randomly.
+                let avail = &g.peers.piece_availability;
+                let min = candidates
+                    .iter()
+                    .map(|n| avail.get(*n).copied().unwrap_or(0))
+                    .min()?;
+                use rand::seq::IteratorRandom;
+                candidates
+                    .iter()
+                    .filter(|n| avail.get(**n).copied().unwrap_or(0) == min)
+                    .choose(&mut rng)
+                    .copied()?
+            };
+
+            self.lengths.validate_piece_index(picked as u32)?
         };
         g.peers.inflight_pieces.insert(n);
         g.chunks.reserve_needed_piece(n);
@@ -271,6 +601,9 @@ impl TorrentState {
         match g.peers.states.get_mut(&handle) {
             Some(s @ &mut PeerState::Connecting(_)) => {
                 *s = PeerState::Live(LivePeerState::new(h.peer_id));
+                // The peer is up again - clear its backoff so a later
+                // disconnect starts counting attempts afresh.
+                g.peers.dead_peers.remove(&handle);
             }
             _ => {
                 warn!("peer {} was in wrong state", handle);
@@ -290,11 +623,29 @@ impl TorrentState {
                 for req in l.inflight_requests {
                     g.chunks.mark_chunk_request_cancelled(req.piece, req.chunk);
                 }
+                // The pieces this peer advertised are one rarer now.
+                if let Some(bf) = l.bitfield.as_ref() {
+                    for i in bf.iter_ones().collect::<Vec<_>>() {
+                        g.peers.decrement_piece_availability(i);
+                    }
+                }
             }
         }
+        // Keep the address around so the reconnect task can retry it after a
+        // backoff instead of forgetting it forever.
+        g.peers.mark_dead(handle, Instant::now());
         true
     }
 
+    /// Addresses of dead peers whose backoff has elapsed. The manager feeds
+    /// these back into `add_peer` to re-initiate a connection.
+    pub fn reconnect_candidates(&self) -> Vec<SocketAddr> {
+        self.locked
+            .write()
+            .peers
+            .reconnect_candidates(Instant::now())
+    }
+
     pub fn get_uploaded(&self) -> u64 {
         self.stats.uploaded.load(Ordering::Relaxed)
     }
@@ -306,6 +657,102 @@ impl TorrentState {
         self.needed - self.get_downloaded()
     }
 
+    /// Flush the current "have" bitfield (plus the `only_files` selection this
+    /// run was started with) to the resume file for this info_hash. Called
+    /// periodically and on clean shutdown.
+    pub fn save_resume_data(
+        &self,
+        resume_dir: &Path,
+        only_files: Option<&[usize]>,
+    ) -> anyhow::Result<()> {
+        let data = {
+            let g = self.locked.read();
+            ResumeData::new(
+                g.chunks.get_have_pieces(),
+                only_files.map(|f| f.to_vec()),
+                self.needed,
+            )
+        };
+        resume::save(resume_dir, &self.info_hash, &data)
+    }
+
+    /// Load a resume file for this info_hash (unless `overwrite` is set) and
+    /// seed the chunk tracker with the already-completed pieces so only the
+    /// remaining bytes get downloaded. The file is ignored if its recorded
+    /// `needed` byte count or `only_files` selection no longer matches this run,
+    /// guarding against a resume file left over from a different run.
+    pub fn load_resume_data(&self, resume_dir: &Path, overwrite: bool, only_files: Option<&[usize]>) {
+        if overwrite {
+            return;
+        }
+        let data = match resume::load(resume_dir, &self.info_hash) {
+            Some(data) => data,
+            None => return,
+        };
+        if data.total_length != self.needed {
+            warn!("resume file for this torrent no longer matches output, ignoring");
+            return;
+        }
+        if data.only_files.as_deref() != only_files {
+            warn!("resume file was written for a different file selection, ignoring");
+            return;
+        }
+        let have = data.bitfield();
+        let have_bytes = have
+            .iter_ones()
+            .filter_map(|n| self.lengths.validate_piece_index(n as u32))
+            .map(|idx| self.lengths.piece_length(idx) as u64)
+            .sum();
+        let mut g = self.locked.write();
+        g.chunks.restore_have_pieces(&have);
+        // Keep the byte counters in step with the restored bitfield, otherwise
+        // get_downloaded()/get_left_to_download() under-report the resumed
+        // pieces - which skews /stats, the seeding check and the rarest-first
+        // initial-phase guard.
+        self.stats.have.store(have_bytes, Ordering::Relaxed);
+        self.stats
+            .downloaded_and_checked
+            .store(have_bytes, Ordering::Relaxed);
+        info!(
+            "resumed {} completed pieces ({} bytes) from {:?}",
+            have.count_ones(),
+            have_bytes,
+            resume_dir
+        );
+    }
+
+    /// One round of the choking algorithm. Ranks interested live peers by their
+    /// recent transfer rate (download rate while leeching, upload rate while
+    /// seeding), unchokes the best [`DEFAULT_UNCHOKE_SLOTS`], and chokes the
+    /// rest. When `rotate_optimistic` is set, one random interested-but-choked
+    /// peer is additionally unchoked so newcomers get a chance to prove
+    /// themselves. Only peers whose choke state actually changes get a message.
+    ///
+    /// The manager calls this every 10 seconds, with `rotate_optimistic` set
+    /// every third round (i.e. every 30 seconds).
+    pub async fn run_choker_round(&self, rotate_optimistic: bool) {
+        let seeding = self.get_left_to_download() == 0;
+        let decisions = {
+            let mut g = self.locked.write();
+            g.peers.compute_choke_decisions(seeding, rotate_optimistic)
+        };
+
+        let mut unordered = FuturesUnordered::new();
+        for (tx, choke) in decisions {
+            unordered.push(async move {
+                let msg = if choke {
+                    Message::Choke
+                } else {
+                    Message::Unchoke
+                };
+                if tx.send(msg).await.is_err() {
+                    // whatever
+                }
+            });
+        }
+        while unordered.next().await.is_some() {}
+    }
+
     // TODO: this is a task per chunk, not good
     pub async fn task_transmit_haves(&self, index: u32) -> anyhow::Result<()> {
         let mut unordered = FuturesUnordered::new();
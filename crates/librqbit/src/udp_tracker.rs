@@ -0,0 +1,239 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use anyhow::Context;
+use log::{debug, warn};
+use reqwest::Url;
+use tokio::net::UdpSocket;
+
+use crate::torrent_state::TorrentState;
+
+// BEP 15 magic connection id, used for the initial connect handshake.
+const PROTOCOL_ID: u64 = 0x0000_0417_2710_1980;
+
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_ERROR: u32 = 3;
+
+// Connection ids are valid for ~2 minutes, re-connect a little earlier to be safe.
+const CONNECTION_ID_LIFETIME: Duration = Duration::from_secs(110);
+
+// How many times to retransmit a request before giving up on it for this round.
+// BEP 15 allows up to 8; we stop earlier since the outer loop keeps retrying.
+const UDP_RETRANSMITS: u32 = 4;
+
+/// The announce event, as defined by BEP 15.
+#[derive(Clone, Copy, Debug)]
+pub enum AnnounceEvent {
+    None = 0,
+    Completed = 1,
+    Started = 2,
+    Stopped = 3,
+}
+
+fn transaction_id() -> u32 {
+    rand::random()
+}
+
+/// A minimal UDP tracker client implementing the BEP 15 connect/announce
+/// protocol. It keeps a cached connection id and refreshes it when it expires.
+pub struct UdpTrackerClient {
+    url: Url,
+    state: Arc<TorrentState>,
+    key: u32,
+}
+
+struct Connection {
+    id: u64,
+    obtained: std::time::Instant,
+}
+
+impl UdpTrackerClient {
+    pub fn new(url: Url, state: Arc<TorrentState>) -> Self {
+        Self {
+            url,
+            state,
+            key: rand::random(),
+        }
+    }
+
+    fn tracker_addr(&self) -> anyhow::Result<String> {
+        let host = self
+            .url
+            .host_str()
+            .context("udp tracker URL has no host")?;
+        let port = self
+            .url
+            .port()
+            .context("udp tracker URL has no port")?;
+        Ok(format!("{}:{}", host, port))
+    }
+
+    /// Send a request and wait for a response, retransmitting on timeout as
+    /// BEP 15 requires (15·2ⁿ seconds for the nth attempt). UDP gives no
+    /// delivery guarantee, so a dropped datagram must not block the task
+    /// forever; after the last retransmit we give up and let the caller's
+    /// retry loop take over.
+    async fn send_and_recv(
+        &self,
+        socket: &UdpSocket,
+        req: &[u8],
+        buf: &mut [u8],
+    ) -> anyhow::Result<usize> {
+        for n in 0..UDP_RETRANSMITS {
+            socket.send(req).await.context("error sending udp request")?;
+            let timeout = Duration::from_secs(15u64 << n);
+            match tokio::time::timeout(timeout, socket.recv(buf)).await {
+                Ok(res) => return res.context("error reading udp response"),
+                Err(_) => {
+                    debug!(
+                        "udp tracker {} timed out after {:?}, retransmitting",
+                        self.url, timeout
+                    );
+                }
+            }
+        }
+        anyhow::bail!("udp tracker did not respond after {} retransmits", UDP_RETRANSMITS)
+    }
+
+    async fn connect(&self, socket: &UdpSocket) -> anyhow::Result<u64> {
+        let tid = transaction_id();
+        let mut req = Vec::with_capacity(16);
+        req.extend_from_slice(&PROTOCOL_ID.to_be_bytes());
+        req.extend_from_slice(&ACTION_CONNECT.to_be_bytes());
+        req.extend_from_slice(&tid.to_be_bytes());
+
+        let mut buf = [0u8; 16];
+        let len = self.send_and_recv(socket, &req, &mut buf).await?;
+        if len < 16 {
+            anyhow::bail!("connect response too short: {} bytes", len);
+        }
+        let action = u32::from_be_bytes(buf[0..4].try_into().unwrap());
+        let rx_tid = u32::from_be_bytes(buf[4..8].try_into().unwrap());
+        if rx_tid != tid {
+            anyhow::bail!("connect transaction id mismatch");
+        }
+        if action != ACTION_CONNECT {
+            anyhow::bail!("unexpected action {} in connect response", action);
+        }
+        Ok(u64::from_be_bytes(buf[8..16].try_into().unwrap()))
+    }
+
+    async fn announce_once(
+        &self,
+        socket: &UdpSocket,
+        connection_id: u64,
+        event: AnnounceEvent,
+    ) -> anyhow::Result<(Duration, Vec<SocketAddr>)> {
+        let downloaded = self.state.get_downloaded();
+        let uploaded = self.state.get_uploaded();
+        let left = self.state.get_left_to_download();
+
+        let tid = transaction_id();
+        let mut req = Vec::with_capacity(98);
+        req.extend_from_slice(&connection_id.to_be_bytes());
+        req.extend_from_slice(&ACTION_ANNOUNCE.to_be_bytes());
+        req.extend_from_slice(&tid.to_be_bytes());
+        req.extend_from_slice(&self.state.info_hash);
+        req.extend_from_slice(&self.state.peer_id);
+        req.extend_from_slice(&downloaded.to_be_bytes());
+        req.extend_from_slice(&left.to_be_bytes());
+        req.extend_from_slice(&uploaded.to_be_bytes());
+        req.extend_from_slice(&(event as u32).to_be_bytes());
+        req.extend_from_slice(&0u32.to_be_bytes()); // IP address, 0 = default.
+        req.extend_from_slice(&self.key.to_be_bytes());
+        req.extend_from_slice(&(-1i32).to_be_bytes()); // num_want, -1 = default.
+        req.extend_from_slice(&0u16.to_be_bytes()); // port, 0 = source port.
+
+        let mut buf = [0u8; 4096];
+        let len = self.send_and_recv(socket, &req, &mut buf).await?;
+        if len < 8 {
+            anyhow::bail!("announce response too short: {} bytes", len);
+        }
+        let action = u32::from_be_bytes(buf[0..4].try_into().unwrap());
+        let rx_tid = u32::from_be_bytes(buf[4..8].try_into().unwrap());
+        if rx_tid != tid {
+            anyhow::bail!("announce transaction id mismatch");
+        }
+        if action == ACTION_ERROR {
+            let msg = String::from_utf8_lossy(&buf[8..len]);
+            anyhow::bail!("tracker returned error: {}", msg);
+        }
+        if action != ACTION_ANNOUNCE || len < 20 {
+            anyhow::bail!("unexpected announce response (action {}, {} bytes)", action, len);
+        }
+        let interval = u32::from_be_bytes(buf[8..12].try_into().unwrap());
+        // buf[12..16] leechers, buf[16..20] seeders - ignored.
+        let mut peers = Vec::new();
+        let mut off = 20;
+        while off + 6 <= len {
+            let ip = std::net::Ipv4Addr::new(buf[off], buf[off + 1], buf[off + 2], buf[off + 3]);
+            let port = u16::from_be_bytes(buf[off + 4..off + 6].try_into().unwrap());
+            peers.push(SocketAddr::from((ip, port)));
+            off += 6;
+        }
+        Ok((Duration::from_secs(interval as u64), peers))
+    }
+
+    /// Announce in a loop forever, feeding discovered peers into `on_peer` and
+    /// sleeping for the tracker-provided interval (or `force_interval` when set).
+    pub async fn announce_forever<F: Fn(SocketAddr)>(
+        &self,
+        force_interval: Option<Duration>,
+        on_peer: F,
+    ) -> anyhow::Result<()> {
+        let addr = self.tracker_addr()?;
+        let socket = UdpSocket::bind("0.0.0.0:0")
+            .await
+            .context("error binding udp socket")?;
+        socket
+            .connect(&addr)
+            .await
+            .with_context(|| format!("error connecting udp socket to {}", addr))?;
+
+        let mut connection: Option<Connection> = None;
+        let mut event = AnnounceEvent::Started;
+        loop {
+            // Obtaining a connection id and announcing share one fallible path,
+            // so a transient connect *or* announce failure just warns, drops the
+            // cached connection id and retries next round rather than killing
+            // the task (which would silence this tracker permanently).
+            let round: anyhow::Result<Duration> = async {
+                let connection_id = match &connection {
+                    Some(c) if c.obtained.elapsed() < CONNECTION_ID_LIFETIME => c.id,
+                    _ => {
+                        let id = self.connect(&socket).await?;
+                        connection = Some(Connection {
+                            id,
+                            obtained: std::time::Instant::now(),
+                        });
+                        id
+                    }
+                };
+                let (interval, peers) = self.announce_once(&socket, connection_id, event).await?;
+                debug!("udp tracker {}: got {} peers", addr, peers.len());
+                for peer in peers {
+                    on_peer(peer);
+                }
+                event = AnnounceEvent::None;
+                Ok(force_interval.unwrap_or(interval))
+            }
+            .await;
+
+            let interval = match round {
+                Ok(interval) => interval,
+                Err(e) => {
+                    warn!("error talking to udp tracker {}: {:#}", addr, e);
+                    connection = None;
+                    force_interval.unwrap_or_else(|| Duration::from_secs(60))
+                }
+            };
+            tokio::time::sleep(interval).await;
+        }
+    }
+}
+
+/// Returns true if the tracker URL should be handled by the UDP client rather
+/// than the HTTP one.
+pub fn is_udp_tracker(url: &Url) -> bool {
+    url.scheme() == "udp"
+}
@@ -5,6 +5,8 @@ use clap::Clap;
 use librqbit::{
     dht::{inforead::read_metainfo_from_peer_receiver, jsdht::JsDht},
     generate_peer_id,
+    http_api::HttpApi,
+    udp_tracker::{self, UdpTrackerClient},
     spawn_utils::{spawn, BlockingSpawner},
     torrent_from_bytes,
     torrent_manager::TorrentManagerBuilder,
@@ -84,6 +86,19 @@ struct Opts {
     #[clap(short = 'i', long = "tracker-refresh-interval")]
     force_tracker_interval: Option<u64>,
 
+    /// If set, start an embedded HTTP server on this address serving JSON
+    /// stats (/stats, /peers) and control routes (/add_tracker, /add_peer) so
+    /// a running torrent can be inspected and driven from scripts.
+    #[clap(long = "http-api")]
+    http_api: Option<SocketAddr>,
+
+    /// The directory where per-torrent resume files (the "have" bitfield keyed
+    /// by info_hash) are stored. On startup an existing resume file is loaded,
+    /// unless --overwrite is set, so a killed download picks up where it left
+    /// off instead of re-hashing from scratch.
+    #[clap(long = "resume-dir", default_value = ".rqbit-resume")]
+    resume_dir: String,
+
     /// Set this flag if you want to use tokio's single threaded runtime.
     /// It MAY perform better, but the main purpose is easier debugging, as time
     /// profilers work better with this one.
@@ -270,15 +285,32 @@ async fn main_info(
         .overwrite(opts.overwrite)
         .spawner(spawner)
         .peer_id(peer_id);
-    if let Some(only_files) = only_files {
-        builder.only_files(only_files);
+    if let Some(ref only_files) = only_files {
+        builder.only_files(only_files.clone());
     }
     if let Some(interval) = opts.force_tracker_interval {
         builder.force_tracker_interval(Duration::from_secs(interval));
     }
     let handle = builder.start_manager()?;
+    let state = handle.state();
+    // Resume from a previous run (no-op under --overwrite) before peers ramp up.
+    let resume_dir = std::path::PathBuf::from(opts.resume_dir.clone());
+    state.load_resume_data(&resume_dir, opts.overwrite, only_files.as_deref());
+    // Route udp:// announce URLs to the BEP 15 client; everything else stays on
+    // the HTTP tracker path.
     for url in trackers {
-        handle.add_tracker(url);
+        if udp_tracker::is_udp_tracker(&url) {
+            let client = UdpTrackerClient::new(url, state.clone());
+            let handle = handle.clone();
+            let force = opts.force_tracker_interval.map(Duration::from_secs);
+            spawn("udp tracker", async move {
+                client
+                    .announce_forever(force, move |peer| handle.add_peer(peer))
+                    .await
+            });
+        } else {
+            handle.add_tracker(url);
+        }
     }
     for peer in initial_peers {
         handle.add_peer(peer);
@@ -293,6 +325,82 @@ async fn main_info(
             Ok(())
         }
     });
+    // Choker: a round every 10s, rotating the optimistic unchoke every 30s.
+    spawn("choker", {
+        let state = state.clone();
+        async move {
+            let mut round: u64 = 0;
+            let mut ticker = tokio::time::interval(Duration::from_secs(10));
+            loop {
+                ticker.tick().await;
+                state.run_choker_round(round % 3 == 0).await;
+                round = round.wrapping_add(1);
+            }
+        }
+    });
+    // Reconnect peers whose backoff has elapsed. reconnect_candidates hands
+    // back the eligible addresses; add_peer re-initiates the connection. The
+    // dead entry is kept (preserving the attempt count) until the peer reaches
+    // Live, and add_if_not_seen rejects a re-dispatch while it's still
+    // connecting, so a failed reconnect keeps growing the backoff.
+    spawn("peer reconnector", {
+        let state = state.clone();
+        let handle = handle.clone();
+        async move {
+            let mut ticker = tokio::time::interval(Duration::from_secs(5));
+            loop {
+                ticker.tick().await;
+                for addr in state.reconnect_candidates() {
+                    handle.add_peer(addr);
+                }
+            }
+        }
+    });
+    // Periodically flush the "have" bitfield so a crash loses little progress.
+    spawn("resume flusher", {
+        let state = state.clone();
+        let resume_dir = resume_dir.clone();
+        let only_files = only_files.clone();
+        async move {
+            let mut ticker = tokio::time::interval(Duration::from_secs(30));
+            loop {
+                ticker.tick().await;
+                if let Err(e) = state.save_resume_data(&resume_dir, only_files.as_deref()) {
+                    warn!("error flushing resume data: {:#}", e);
+                }
+            }
+        }
+    });
+    if let Some(addr) = opts.http_api {
+        // Control requests arrive over channels and are forwarded to the
+        // manager handle, mirroring the DHT peer-adder above.
+        let (tracker_tx, mut tracker_rx) = tokio::sync::mpsc::unbounded_channel::<Url>();
+        let (peer_tx, mut peer_rx) = tokio::sync::mpsc::unbounded_channel::<SocketAddr>();
+        spawn("HTTP API tracker adder", {
+            let handle = handle.clone();
+            async move {
+                while let Some(url) = tracker_rx.recv().await {
+                    handle.add_tracker(url);
+                }
+                Ok(())
+            }
+        });
+        spawn("HTTP API peer adder", {
+            let handle = handle.clone();
+            async move {
+                while let Some(peer) = peer_rx.recv().await {
+                    handle.add_peer(peer);
+                }
+                Ok(())
+            }
+        });
+        let api = HttpApi::new(handle.state(), tracker_tx, peer_tx);
+        spawn("HTTP API", async move { api.serve(addr).await });
+    }
     handle.wait_until_completed().await?;
+    // Final flush on clean shutdown.
+    if let Err(e) = state.save_resume_data(&resume_dir, only_files.as_deref()) {
+        warn!("error flushing resume data on shutdown: {:#}", e);
+    }
     Ok(())
 }
\ No newline at end of file